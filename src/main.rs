@@ -13,11 +13,14 @@ extern crate crossbeam_channel as channel;
 #[macro_use]
 extern crate structopt;
 extern crate num_cpus;
+extern crate ffmpeg_next as ffmpeg;
 
 pub mod sample;
 pub mod camera;
 pub mod stats;
 pub mod pipe;
+pub mod preview;
+pub mod output;
 
 use failure::Error;
 
@@ -38,10 +41,58 @@ struct Params {
     tile_size: u32,
     #[structopt(long="bounces", default_value="12", help="maximum length of light path")]
     bounce_limit: usize,
-    #[structopt(name="OUTPUT", help="output image filename where \"%n\" is the frame number")]
+    #[structopt(long="fps", default_value="30", help="frame rate when OUTPUT is a video file")]
+    fps: u32,
+    #[structopt(short="i", long="interactive", help="free-fly the camera with WASD/mouse instead of rendering the animation")]
+    interactive: bool,
+    #[structopt(name="OUTPUT", help="output filename; an image template with \"%n\" for the frame number, or a .mp4/.webm/.mov video")]
     output: String,
 }
 
+/// Mutable free-fly camera state for `--interactive` mode: WASD/arrows move the
+/// eye in its own facing frame, mouse-drag aims it, and `[`/`]` pull the focal
+/// plane in and out.
+struct FlyCamera {
+    eye: nalg::Point3<f64>,
+    yaw: f64,
+    pitch: f64,
+    focus: f64,
+}
+
+impl FlyCamera {
+    /// Unit look direction for the current yaw/pitch.
+    fn look(&self) -> nalg::Vector3<f64> {
+        nalg::Vector3::new(
+            self.yaw.cos() * self.pitch.cos(),
+            self.pitch.sin(),
+            self.yaw.sin() * self.pitch.cos(),
+        )
+    }
+
+    /// Observer frame looking along `look()` from the current eye point.
+    fn observer_frame(&self) -> nalg::Isometry3<f64> {
+        let target = self.eye + self.look();
+        nalg::Isometry3::new_observer_frame(&self.eye, &target, &nalg::Vector3::new(0., 1., 0.))
+    }
+
+    /// Move the eye relative to its current facing: `forward` along the look
+    /// direction, `right` across it, `up` along the camera's own up (square to
+    /// both, so it tilts with the pitch).
+    fn translate(&mut self, forward: f64, right: f64, up: f64) {
+        let f = self.look();
+        let r = f.cross(&nalg::Vector3::new(0., 1., 0.)).normalize();
+        let u = r.cross(&f).normalize();
+        self.eye += f * forward + r * right + u * up;
+    }
+
+    /// Aim by a mouse-drag delta, clamping pitch just shy of straight up/down.
+    fn aim(&mut self, dyaw: f64, dpitch: f64) {
+        use std::f64::consts::FRAC_PI_2;
+        self.yaw += dyaw;
+        self.pitch = (self.pitch + dpitch).max(-FRAC_PI_2 + 1e-3).min(FRAC_PI_2 - 1e-3);
+    }
+}
+
 fn main() -> Result<(), Error> {
     use nalg::{Isometry3, Point3, Vector3};
     use camera::{DefocusCamera, PerspectiveCamera};
@@ -49,17 +100,24 @@ fn main() -> Result<(), Error> {
     use std::f64::consts::{FRAC_PI_4, PI};
     use failure::format_err;
     use indicatif::{ProgressBar, ProgressStyle};
-    use sdl2::rect::Rect;
-    use sdl2::event::Event;
+    use sdl2::pixels::Color;
+    use sdl2::event::{Event, WindowEvent};
     use sdl2::keyboard::Keycode;
+    use sdl2::video::FullscreenType;
     use structopt::StructOpt;
-    use std::collections::HashMap;
+    use std::collections::{HashMap, HashSet};
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use std::time::{Duration, Instant};
     use palette::{Pixel, LinSrgb, named as colors};
 
     // parse args
     let params = Params::from_args();
     let threads = params.threads.unwrap_or(num_cpus::get());
     let frame_count = params.frames;
+    // render resolution is independent of the preview window size
+    let render_width = params.width;
+    let render_height = params.height;
     let sample_params = sample::SampleParams {
         samples: params.samples,
         bounce_limit: params.bounce_limit,
@@ -71,148 +129,318 @@ fn main() -> Result<(), Error> {
         tile_queue: threads * 2,
         threads: threads,
     };
-    let output_template = params.output;
-    let output_path = move |n| output_template.replace("%n", &format!("{}", n));
+    // pick an output backend from the OUTPUT extension: numbered images or a
+    // single video file fed frames in order
+    let sink = Rc::new(RefCell::new(output::Sink::new(&params.output, params.fps)?));
 
-    // function to create world for each frame
-    let per_frame_world = move |index: u32| -> Result<Option<pipe::FrameData>, Error> {
-        // check end of animation or close
-        if index >= frame_count { return Ok(None) }
-
-        // create camera
-        let angle = 2. * PI * (index as f64 / frame_count  as f64);
-        let cam = PerspectiveCamera::new(
-            Isometry3::new_observer_frame(
-                &Point3::new(12., 8., 12.),
-                &Point3::new(0., 0., 0.),
-                &Vector3::new(0., 1., 0.),
-            ),
-            FRAC_PI_4,
-            0.1,
-            100.,
-        );
-        let cam = DefocusCamera::new(cam, 14.);
-
-        // create world
-        let world = World {
-            objects: vec![
-                //Format: (x, y, z, radius, emmisivity(r,g,b), reflectivity)
-                Object::new(0., -2., 0., 3., LinSrgb::new(0.894, 0.345, 0.925) * 0.25f32, 0.5),
-                Object::new(0., 3., 0., 1.5, LinSrgb::new(0.8, 1., 0.8) * 0.9f32, 0.75),
-                Object::new(4., -2.25 * angle.sin(), 0., 1., LinSrgb::new(1.0, 0.2, 0.2) * 0.75f32 * (((angle.cos() + 1.) as f32) / 2f32), 0.95),
-                Object::new(-4., 2.25 * angle.sin(), 0., 1., LinSrgb::new(0.2, 0.2, 1.) * 0.75f32 * (((angle.sin() + 1.) as f32) / 2f32), 0.95),
-                Object::new(4. * angle.sin(), 0., 4. * angle.cos(), 1., LinSrgb::new(0., 0., 0.), 0.95),
-                Object::new(-4. * angle.sin(), 0., -4. * angle.cos(), 1., LinSrgb::new(0., 0., 0.), 0.05),
-            ],
-            ambient: colors::DARKSLATEGREY.into_format::<f32>().into_linear() * 0.4,
-            margin: 0.00001,
-        };
-
-
-        // final world and camera data
-        Ok(Some(pipe::FrameData {
-            world,
-            cam,
-            params: sample_params,
+    // shared free-fly camera state, driven by the event tick in interactive mode
+    let interactive = params.interactive;
+    let cam_state = {
+        let eye = Point3::new(12., 8., 12.);
+        let dir = (Point3::new(0., 0., 0.) - eye).normalize();
+        Rc::new(RefCell::new(FlyCamera {
+            eye,
+            yaw: dir.z.atan2(dir.x),
+            pitch: dir.y.asin(),
+            focus: 14.,
         }))
     };
 
+    // function to create world for each frame
+    let per_frame_world = {
+        let cam_state = cam_state.clone();
+        move |index: u32| -> Result<Option<pipe::FrameData>, Error> {
+            // animation mode stops after the requested frame count; interactive
+            // mode renders the live camera until the user quits
+            if !interactive && index >= frame_count { return Ok(None) }
+
+            // create camera: a live free-fly frame, or the orbit keyframe
+            let (cam, angle) = if interactive {
+                let c = cam_state.borrow();
+                let cam = PerspectiveCamera::new(c.observer_frame(), FRAC_PI_4, 0.1, 100.);
+                (DefocusCamera::new(cam, c.focus), 0.)
+            } else {
+                let angle = 2. * PI * (index as f64 / frame_count as f64);
+                let cam = PerspectiveCamera::new(
+                    Isometry3::new_observer_frame(
+                        &Point3::new(12., 8., 12.),
+                        &Point3::new(0., 0., 0.),
+                        &Vector3::new(0., 1., 0.),
+                    ),
+                    FRAC_PI_4,
+                    0.1,
+                    100.,
+                );
+                (DefocusCamera::new(cam, 14.), angle)
+            };
+
+            // create world
+            let world = World {
+                objects: vec![
+                    //Format: (x, y, z, radius, emmisivity(r,g,b), reflectivity)
+                    Object::new(0., -2., 0., 3., LinSrgb::new(0.894, 0.345, 0.925) * 0.25f32, 0.5),
+                    Object::new(0., 3., 0., 1.5, LinSrgb::new(0.8, 1., 0.8) * 0.9f32, 0.75),
+                    Object::new(4., -2.25 * angle.sin(), 0., 1., LinSrgb::new(1.0, 0.2, 0.2) * 0.75f32 * (((angle.cos() + 1.) as f32) / 2f32), 0.95),
+                    Object::new(-4., 2.25 * angle.sin(), 0., 1., LinSrgb::new(0.2, 0.2, 1.) * 0.75f32 * (((angle.sin() + 1.) as f32) / 2f32), 0.95),
+                    Object::new(4. * angle.sin(), 0., 4. * angle.cos(), 1., LinSrgb::new(0., 0., 0.), 0.95),
+                    Object::new(-4. * angle.sin(), 0., -4. * angle.cos(), 1., LinSrgb::new(0., 0., 0.), 0.05),
+                ],
+                ambient: colors::DARKSLATEGREY.into_format::<f32>().into_linear() * 0.4,
+                margin: 0.00001,
+            };
+
+            // final world and camera data
+            Ok(Some(pipe::FrameData {
+                world,
+                cam,
+                params: sample_params,
+            }))
+        }
+    };
+
     // create preview window
     let sdl = sdl2::init().map_err(|err| format_err!("Could not initialize SDL: {}", err))?;
     let mut events = sdl.event_pump().map_err(|err| format_err!("Could get SDL events: {}", err))?;
     let video = sdl.video().map_err(|err| format_err!("Could get SDL video: {}", err))?;
-    let window = video.window("Sidequest Render Preview", params.width, params.height).build()?;
+    let window = video.window("Sidequest Render Preview", params.width, params.height)
+        .resizable()
+        .build()?;
     let mut canvas = window.into_canvas().build()?;
     canvas.clear();
     canvas.present();
     let texture_create = canvas.texture_creator();
-    let mut tile_texture = texture_create.create_texture_static(
-        // we could store output as simple RGB, but OpenGL endianess stupidity
-        // requires we use an alpha component, and pass it the backwards/wrong
-        // texture format. Don't ask me why.
-        Some(sdl2::pixels::PixelFormatEnum::ABGR8888),
-        params.tile_size,
-        params.tile_size,
-    )?;
-
-    // setup progress bar
-    let sty = ProgressStyle::default_bar().template("[{eta}] {wide_bar} {pos}/{len}");
-    let tiles_bar = ProgressBar::new(render_params.tiles_per_frame() as u64 * frame_count as u64);
-    tiles_bar.set_style(sty);
+
+    // Negotiate a texture format with the renderer instead of hardcoding one:
+    // the `preview` helper converts the tracer's canonical RGBA8 buffers into
+    // whatever channel order / endianness the chosen format expects.
+    let (pixel_format, shuffle) = preview::PixelShuffle::negotiate(canvas.default_pixel_format());
+
+    // A single streaming texture the size of a whole frame. Finished tiles are
+    // blitted into each frame's CPU-side buffer as they arrive; the texture is
+    // re-uploaded and presented only on the event tick below, decoupling render
+    // throughput from SDL present calls.
+    let preview_texture = Rc::new(RefCell::new(texture_create.create_texture_streaming(
+        pixel_format,
+        params.width,
+        params.height,
+    )?));
+
+    // start from a clean black texture so a resize/redraw before the first tile
+    // never blits uninitialized contents
+    preview_texture.borrow_mut()
+        .with_lock(None, |bytes, _| for b in bytes.iter_mut() { *b = 0 })
+        .map_err(|e| format_err!("could not clear preview texture: {}", e))?;
+
+    // setup progress: a bar with a known total for the animation, or a bare
+    // spinner for interactive mode, which re-renders indefinitely and has no
+    // frame count to size a bar against
+    let tiles_bar = if interactive {
+        let bar = ProgressBar::new_spinner();
+        bar.set_style(ProgressStyle::default_spinner().template("{spinner} rendering... ({pos} tiles)"));
+        bar
+    } else {
+        let bar = ProgressBar::new(render_params.tiles_per_frame() as u64 * frame_count as u64);
+        bar.set_style(ProgressStyle::default_bar().template("[{eta}] {wide_bar} {pos}/{len}"));
+        bar
+    };
     tiles_bar.tick();
 
-    // setup outputs
-    let mut frames = HashMap::new();
+    // the in-progress frame's buffer, keyed by frame number, and the set of
+    // frames touched since the last present (shared between `on_tile` and the
+    // event tick). `render_pipeline` renders one frame at a time, but a frame's
+    // last tiles and the next frame's first tiles can land between two ticks,
+    // so `dirty` may hold more than one frame number; the map keeps each
+    // frame's pixels apart.
+    let frames = Rc::new(RefCell::new(HashMap::new()));
+    let dirty: Rc<RefCell<HashSet<u32>>> = Rc::new(RefCell::new(HashSet::new()));
 
     // tile finished function
-    let on_tile = |tile: pipe::Tile| -> Result<(), Error> {
-        // update progress bar
-        tiles_bar.inc(1);
+    let on_tile = {
+        let frames = frames.clone();
+        let dirty = dirty.clone();
+        let sink = sink.clone();
+        move |tile: pipe::Tile| -> Result<(), Error> {
+            // update progress bar
+            tiles_bar.inc(1);
 
-        // save finished frame, and remove
-        let done;
-        {
+            // copy the tile into its frame's full-frame buffer
+            let mut frames = frames.borrow_mut();
             let frame = frames.entry(tile.frame_num)
                 .or_insert_with(|| render_params.uninitialized_frame());
             frame.tile_ready(&tile);
-            done = frame.is_done();
-            if done { image::save_buffer(
-                output_path(tile.frame_num),
-                Pixel::into_raw_slice(&frame.buf.buf),
+
+            // hand finished frames to the output backend
+            if frame.is_done() { sink.borrow_mut().frame(
+                tile.frame_num,
                 frame.buf.width() as u32,
                 frame.buf.height() as u32,
-                image::ColorType::RGBA(8),
+                Pixel::into_raw_slice(&frame.buf.buf),
             )? }
-        }
-        if done { frames.remove(&tile.frame_num); }
 
-        // calculate buffer information
-        let width = tile.buf.width() as u32;
-        let height = tile.buf.height() as u32;
-        let texrect = Rect::new(0, 0, width, height);
-        let canrect = Rect::new(tile.left as i32, tile.top as i32, width, height);
-        let bytes = Pixel::into_raw_slice(&tile.buf.buf);
+            // flag the preview dirty; upload and present happen on the next tick
+            dirty.borrow_mut().insert(tile.frame_num);
 
-        // update texture
-        tile_texture.update(Some(texrect), bytes, tile.buf.stride() * 4)?;
-
-        // copy texture to screen
-        canvas.copy(
-            &tile_texture,
-            Some(texrect),
-            Some(canrect),
-        ).map_err(|e| format_err!("could preview tile: {}", e))?;
-
-        // display to user
-        canvas.present();
-
-        Ok(())
+            Ok(())
+        }
     };
 
     // run render pipeline
-    pipe::render_pipeline(
+    let render_result = pipe::render_pipeline(
         // create frames to render
         per_frame_world,
         // use rendered tiles
         on_tile,
-        // poll window events
-        || {
-            // check for close
-            for event in events.poll_iter() {
-            match event {
-                    Event::Quit { .. } |
-                    Event::KeyDown { keycode: Some(Keycode::Escape), .. } |
-                    Event::KeyDown { keycode: Some(Keycode::Q), .. } => return pipe::TickResult::Exit,
-                    _ => (),
+        // poll window events and refresh the preview
+        {
+            let frames = frames.clone();
+            let dirty = dirty.clone();
+            let preview_texture = preview_texture.clone();
+            let cam_state = cam_state.clone();
+            // free-fly motion per key press and per pixel of mouse-drag
+            const STEP: f64 = 0.5;
+            const FOCUS_STEP: f64 = 0.5;
+            const AIM: f64 = 0.005;
+            // wait for the camera to sit still this long before restarting
+            // sampling, so a key held down or a mouse drag coalesces into one
+            // restart instead of tearing down and re-rendering the whole frame
+            // on every single input event
+            const SETTLE: Duration = Duration::from_millis(150);
+            let mut camera_dirty = false;
+            let mut last_moved = Instant::now();
+            move || -> pipe::TickResult {
+                // handle window events; `redraw` forces a present even without
+                // new tiles so the scaled blit tracks the current window size.
+                let mut redraw = false;
+                for event in events.poll_iter() {
+                    match event {
+                        Event::Quit { .. } |
+                        Event::KeyDown { keycode: Some(Keycode::Escape), .. } |
+                        Event::KeyDown { keycode: Some(Keycode::Q), .. } => return pipe::TickResult::Exit,
+                        // toggle desktop fullscreen
+                        Event::KeyDown { keycode: Some(Keycode::F), .. } => {
+                            let window = canvas.window_mut();
+                            let mode = match window.fullscreen_state() {
+                                FullscreenType::Off => FullscreenType::Desktop,
+                                _ => FullscreenType::Off,
+                            };
+                            window.set_fullscreen(mode).ok();
+                            redraw = true;
+                        }
+                        // repaint when the window is resized
+                        Event::Window { win_event: WindowEvent::Resized(..), .. } |
+                        Event::Window { win_event: WindowEvent::SizeChanged(..), .. } => redraw = true,
+
+                        // --- free-fly controls (interactive mode only) ---
+                        Event::KeyDown { keycode: Some(key), .. } if interactive => {
+                            let mut cam = cam_state.borrow_mut();
+                            match key {
+                                Keycode::W | Keycode::Up => cam.translate(STEP, 0., 0.),
+                                Keycode::S | Keycode::Down => cam.translate(-STEP, 0., 0.),
+                                Keycode::A | Keycode::Left => cam.translate(0., -STEP, 0.),
+                                Keycode::D | Keycode::Right => cam.translate(0., STEP, 0.),
+                                Keycode::Space => cam.translate(0., 0., STEP),
+                                Keycode::LShift => cam.translate(0., 0., -STEP),
+                                Keycode::LeftBracket => cam.focus = (cam.focus - FOCUS_STEP).max(0.),
+                                Keycode::RightBracket => cam.focus += FOCUS_STEP,
+                                _ => continue,
+                            }
+                            camera_dirty = true;
+                            last_moved = Instant::now();
+                        }
+                        // drag with the left mouse button to aim
+                        Event::MouseMotion { mousestate, xrel, yrel, .. }
+                            if interactive && mousestate.left() => {
+                            cam_state.borrow_mut().aim(xrel as f64 * AIM, -yrel as f64 * AIM);
+                            camera_dirty = true;
+                            last_moved = Instant::now();
+                        }
+                        _ => (),
+                    }
+                }
+
+                // only drop the stale frame and resample once the camera has
+                // sat still for `SETTLE`, so continuous motion (a held key, a
+                // drag in progress) coalesces into a single restart of the
+                // full-quality render instead of tearing one down on every event
+                if camera_dirty && last_moved.elapsed() >= SETTLE {
+                    camera_dirty = false;
+                    frames.borrow_mut().clear();
+                    dirty.borrow_mut().clear();
+                    return pipe::TickResult::Restart;
                 }
+
+                // re-upload the frames touched since the last tick
+                let touched: Vec<u32> = dirty.borrow_mut().drain().collect();
+                let mut frames = frames.borrow_mut();
+                let result: Result<(), Error> = (|| {
+                    if !touched.is_empty() {
+                        // upload the lowest-numbered touched frame, so a frame
+                        // that just finished is shown before the next one's
+                        // first tiles; any other touched frame is uploaded on
+                        // a later tick.
+                        if let Some(frame) = touched.iter().min().and_then(|n| frames.get(n)) {
+                            let stride = frame.buf.stride() * 4;
+                            let rows = frame.buf.height();
+                            let row_bytes = frame.buf.width() * 4;
+                            let src = Pixel::into_raw_slice(&frame.buf.buf);
+                            let mut texture = preview_texture.borrow_mut();
+                            texture.with_lock(None, |bytes, pitch| {
+                                // source and texture rows may have different padding
+                                let row_bytes = row_bytes.min(pitch);
+                                for row in 0..rows {
+                                    let s = row * stride;
+                                    let d = row * pitch;
+                                    shuffle.convert_row(&src[s..s + row_bytes], &mut bytes[d..d + row_bytes]);
+                                }
+                            }).map_err(|e| format_err!("could not lock preview texture: {}", e))?;
+                            redraw = true;
+                        }
+                    }
+
+                    // scale the full-frame texture into an aspect-preserving,
+                    // letterboxed rect fitted to the current window size
+                    let (ow, oh) = canvas.output_size()
+                        .map_err(|e| format_err!("could not query window size: {}", e))?;
+                    // skip painting a minimized / zero-size window
+                    if redraw && ow > 0 && oh > 0 {
+                        let dst = preview::fit_rect((render_width, render_height), (ow, oh));
+                        let texture = preview_texture.borrow();
+                        canvas.set_draw_color(Color::RGB(0, 0, 0));
+                        canvas.clear();
+                        canvas.copy(&texture, None, Some(dst))
+                            .map_err(|e| format_err!("could not preview frame: {}", e))?;
+                        canvas.present();
+                    }
+                    Ok(())
+                })();
+                // a preview hiccup should never tear down a long render
+                if let Err(e) = result { eprintln!("preview error: {}", e); }
+
+                // drop the shown frame once it is fully rendered; mark the other
+                // touched frames dirty again so the next tick uploads them
+                let shown = touched.iter().min().cloned();
+                for n in touched {
+                    if Some(n) != shown {
+                        if frames.contains_key(&n) { dirty.borrow_mut().insert(n); }
+                    } else if frames.get(&n).map(|f| f.is_done()).unwrap_or(false) {
+                        frames.remove(&n);
+                    }
+                }
+
+                pipe::TickResult::Run
             }
-            pipe::TickResult::Run
         },
         // poll rate
         100,
         // render options
         render_params,
-    )?;
+    );
+
+    // always flush the output backend, even if the pipeline errored, so a video
+    // file still gets its encoder drained and container trailer written
+    let finish_result = sink.borrow_mut().finish();
+    render_result?;
+    finish_result?;
 
     // done!
     tiles_bar.finish();
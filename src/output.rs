@@ -0,0 +1,303 @@
+//! Frame output backends.
+//!
+//! A sink is handed each completed frame as soon as its last tile lands, with
+//! no guarantee from the caller about what order that happens in — today's
+//! scheduler renders one frame at a time so frames always arrive in order,
+//! but the sink doesn't rely on that. The image backend is order-independent
+//! (one file per frame, named through the `%n` template); the video backend
+//! must feed frames to the encoder in ascending `frame_num`, so it holds
+//! finished frames in a small reorder buffer until the next expected index is
+//! ready.
+//!
+//! The video backend is built on `ffmpeg-next`, which links against the
+//! system's libav* development libraries (`libavformat`, `libavcodec`,
+//! `libavutil`, `libswscale`) at build time — install your distro's
+//! `ffmpeg`/`libav` -dev packages before building with this feature.
+//! `Encoder::new` asks the output container for its *default* codec rather
+//! than naming one explicitly; for `.mp4` that's reliably H.264, but for
+//! `.webm` it depends on how the local libav build was configured (VP8/VP9
+//! support is not guaranteed). If the container has no default video codec
+//! available, `Encoder::new` surfaces that as a `"no video encoder for {path}"`
+//! error rather than silently falling back to another format.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use failure::{Error, format_err};
+
+/// Pick an output backend from the `OUTPUT` filename: a `.mp4`/`.webm`/`.mov`
+/// extension selects the video encoder, anything else keeps the per-frame image
+/// path.
+pub enum Sink {
+    /// One image file per frame; `template` still carries the `%n` placeholder.
+    Images { template: String },
+    /// A single muxed video file fed frames in order.
+    Video(Video),
+}
+
+impl Sink {
+    pub fn new(output: &str, fps: u32) -> Result<Sink, Error> {
+        let ext = Path::new(output)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match ext.as_ref().map(String::as_str) {
+            Some("mp4") | Some("webm") | Some("mov") => Ok(Sink::Video(Video::new(output, fps)?)),
+            _ => Ok(Sink::Images { template: output.to_owned() }),
+        }
+    }
+
+    /// Hand a finished frame to the backend. `rgba` is canonical packed RGBA8.
+    pub fn frame(&mut self, num: u32, width: u32, height: u32, rgba: &[u8]) -> Result<(), Error> {
+        match self {
+            Sink::Images { template } => {
+                let path = template.replace("%n", &format!("{}", num));
+                image::save_buffer(path, rgba, width, height, image::ColorType::RGBA(8))?;
+                Ok(())
+            }
+            Sink::Video(video) => video.frame(num, width, height, rgba),
+        }
+    }
+
+    /// Flush any buffered frames and finalize the output. For video this drains
+    /// the encoder and writes the container trailer.
+    pub fn finish(&mut self) -> Result<(), Error> {
+        match self {
+            Sink::Images { .. } => Ok(()),
+            Sink::Video(video) => video.finish(),
+        }
+    }
+}
+
+/// An ordered frame held back until the encoder is ready for its index.
+struct Pending {
+    width: u32,
+    height: u32,
+    rgba: Vec<u8>,
+}
+
+/// Reassembles out-of-order, index-tagged items into ascending order: each
+/// `push` releases every item that has become contiguous with the last one
+/// released, and nothing after a gap is released until the gap is filled.
+struct ReorderBuffer<T> {
+    next: u32,
+    pending: HashMap<u32, T>,
+}
+
+impl<T> ReorderBuffer<T> {
+    fn new() -> ReorderBuffer<T> {
+        ReorderBuffer { next: 0, pending: HashMap::new() }
+    }
+
+    /// Insert `item` under `num` and return every now-contiguous item, in
+    /// ascending order.
+    fn push(&mut self, num: u32, item: T) -> Vec<T> {
+        self.pending.insert(num, item);
+        let mut ready = Vec::new();
+        while let Some(item) = self.pending.remove(&self.next) {
+            ready.push(item);
+            self.next += 1;
+        }
+        ready
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    fn len(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+/// ffmpeg-backed video sink. Frames may arrive in any order and are released
+/// to the encoder strictly in ascending `frame_num`.
+pub struct Video {
+    path: String,
+    fps: u32,
+    reorder: ReorderBuffer<Pending>,
+    enc: Option<Encoder>,
+}
+
+impl Video {
+    pub fn new(path: &str, fps: u32) -> Result<Video, Error> {
+        if fps == 0 { return Err(format_err!("video fps must be non-zero")); }
+        ffmpeg::init().map_err(|e| format_err!("could not initialize ffmpeg: {}", e))?;
+        Ok(Video {
+            path: path.to_owned(),
+            fps,
+            reorder: ReorderBuffer::new(),
+            enc: None,
+        })
+    }
+
+    pub fn frame(&mut self, num: u32, width: u32, height: u32, rgba: &[u8]) -> Result<(), Error> {
+        for frame in self.reorder.push(num, Pending { width, height, rgba: rgba.to_vec() }) {
+            // lazily build the encoder once we know the frame size
+            if self.enc.is_none() {
+                self.enc = Some(Encoder::new(&self.path, self.fps, frame.width, frame.height)?);
+            }
+            self.enc.as_mut().unwrap().push(&frame.rgba)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> Result<(), Error> {
+        if !self.reorder.is_empty() {
+            // a gap at `next` means some earlier frame never finished (e.g.
+            // the user quit mid-render); the trailing frames can't be emitted in
+            // order, so the video stops at the last contiguous frame
+            eprintln!(
+                "warning: {} rendered frame(s) after a gap at frame {} were not written to the video",
+                self.reorder.len(), self.reorder.next,
+            );
+        }
+        if let Some(mut enc) = self.enc.take() {
+            enc.finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// Thin wrapper over an ffmpeg output context and its single video stream.
+struct Encoder {
+    octx: ffmpeg::format::context::Output,
+    encoder: ffmpeg::encoder::video::Video,
+    scaler: ffmpeg::software::scaling::Context,
+    stream: usize,
+    time_base: ffmpeg::Rational,
+    width: u32,
+    height: u32,
+    pts: i64,
+}
+
+impl Encoder {
+    fn new(path: &str, fps: u32, width: u32, height: u32) -> Result<Encoder, Error> {
+        use ffmpeg::{codec, encoder, format, media};
+        use ffmpeg::format::Pixel as AvPixel;
+        use ffmpeg::software::scaling;
+
+        let mut octx = format::output(&path)
+            .map_err(|e| format_err!("could not open output {}: {}", path, e))?;
+        let global_header = octx.format().flags().contains(format::Flags::GLOBAL_HEADER);
+
+        let codec = encoder::find(octx.format().codec(path, media::Type::Video))
+            .ok_or_else(|| format_err!("no video encoder for {}", path))?;
+        let time_base = ffmpeg::Rational::new(1, fps as i32);
+
+        let mut stream = octx.add_stream(codec)?;
+        let mut enc = codec::context::Context::from_parameters(stream.parameters())?
+            .encoder()
+            .video()?;
+        enc.set_width(width);
+        enc.set_height(height);
+        enc.set_format(AvPixel::YUV420P);
+        enc.set_time_base(time_base);
+        enc.set_frame_rate(Some(ffmpeg::Rational::new(fps as i32, 1)));
+        if global_header {
+            enc.set_flags(codec::Flags::GLOBAL_HEADER);
+        }
+
+        let encoder = enc.open_as(codec)?;
+        stream.set_parameters(&encoder);
+        stream.set_time_base(time_base);
+        let stream = stream.index();
+
+        octx.write_header()?;
+
+        let scaler = scaling::Context::get(
+            AvPixel::RGBA, width, height,
+            AvPixel::YUV420P, width, height,
+            scaling::Flag::BILINEAR,
+        )?;
+
+        Ok(Encoder {
+            octx,
+            encoder,
+            scaler,
+            stream,
+            time_base,
+            width,
+            height,
+            pts: 0,
+        })
+    }
+
+    fn push(&mut self, rgba: &[u8]) -> Result<(), Error> {
+        use ffmpeg::format::Pixel as AvPixel;
+        use ffmpeg::util::frame::video::Video as AvFrame;
+
+        // load the packed RGBA frame, respecting ffmpeg's row alignment
+        let mut src = AvFrame::new(AvPixel::RGBA, self.width, self.height);
+        let stride = src.stride(0);
+        let row = self.width as usize * 4;
+        {
+            let data = src.data_mut(0);
+            for y in 0..self.height as usize {
+                data[y * stride..y * stride + row]
+                    .copy_from_slice(&rgba[y * row..y * row + row]);
+            }
+        }
+
+        // convert to the encoder's pixel format and stamp presentation order
+        let mut dst = AvFrame::empty();
+        self.scaler.run(&src, &mut dst)?;
+        dst.set_pts(Some(self.pts));
+        self.pts += 1;
+
+        self.encoder.send_frame(&dst)?;
+        self.receive()
+    }
+
+    /// Drain and mux whatever packets the encoder has ready.
+    fn receive(&mut self) -> Result<(), Error> {
+        let mut packet = ffmpeg::Packet::empty();
+        while self.encoder.receive_packet(&mut packet).is_ok() {
+            packet.set_stream(self.stream);
+            packet.rescale_ts(self.time_base, self.octx.stream(self.stream).unwrap().time_base());
+            packet.write_interleaved(&mut self.octx)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        self.encoder.send_eof()?;
+        self.receive()?;
+        self.octx.write_trailer()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReorderBuffer;
+
+    #[test]
+    fn releases_in_order_as_items_arrive_out_of_order() {
+        let mut buf = ReorderBuffer::new();
+        assert_eq!(buf.push(2, "c"), Vec::<&str>::new());
+        assert_eq!(buf.push(0, "a"), vec!["a"]);
+        assert_eq!(buf.push(1, "b"), vec!["b", "c"]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn holds_everything_after_a_gap() {
+        let mut buf = ReorderBuffer::new();
+        buf.push(0, "a");
+        assert_eq!(buf.push(2, "c"), Vec::<&str>::new());
+        assert_eq!(buf.push(3, "d"), Vec::<&str>::new());
+        assert_eq!(buf.len(), 2);
+        // filling the gap releases everything contiguous with it
+        assert_eq!(buf.push(1, "b"), vec!["b", "c", "d"]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn duplicate_index_overwrites_pending_item() {
+        let mut buf = ReorderBuffer::new();
+        buf.push(1, "stale");
+        assert_eq!(buf.push(1, "fresh"), Vec::<&str>::new());
+        assert_eq!(buf.push(0, "a"), vec!["a", "fresh"]);
+    }
+}
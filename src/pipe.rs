@@ -0,0 +1,256 @@
+//! Tile-based render scheduler.
+//!
+//! `render_pipeline` hands out one [`FrameData`] per frame index, splits it
+//! into `tile_size`-square tiles, farms those out to a worker pool over a
+//! channel, and streams finished [`Tile`]s back to the caller through
+//! `on_tile` *on the calling thread* — workers never touch `on_tile`
+//! themselves, so it's free to close over non-`Send` preview state the way
+//! `main`'s does. Between batches it polls `tick` on `poll_ms` centers so the
+//! caller can drive a preview window; a [`TickResult`] lets `tick` steer the
+//! run.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use imgref::ImgVec;
+use palette::Srgba;
+use failure::Error;
+
+use camera::{DefocusCamera, PerspectiveCamera};
+use sample::{SampleParams, World};
+use channel;
+
+/// Parameters controlling how frames are sized and split into tiles.
+#[derive(Clone, Copy, Debug)]
+pub struct RenderParams {
+    pub width: usize,
+    pub height: usize,
+    pub tile_size: usize,
+    pub tile_queue: usize,
+    pub threads: usize,
+}
+
+impl RenderParams {
+    /// Number of tiles a single frame is split into.
+    pub fn tiles_per_frame(&self) -> usize {
+        let tiles_x = (self.width + self.tile_size - 1) / self.tile_size;
+        let tiles_y = (self.height + self.tile_size - 1) / self.tile_size;
+        tiles_x * tiles_y
+    }
+
+    /// An empty full-frame buffer ready to receive tiles.
+    pub fn uninitialized_frame(&self) -> Frame {
+        Frame {
+            buf: ImgVec::new(
+                vec![Srgba::new(0u8, 0, 0, 255); self.width * self.height],
+                self.width,
+                self.height,
+            ),
+            tiles_remaining: self.tiles_per_frame(),
+        }
+    }
+
+    fn tile_rects(&self) -> Vec<(usize, usize, usize, usize)> {
+        let mut rects = Vec::with_capacity(self.tiles_per_frame());
+        let mut top = 0;
+        while top < self.height {
+            let h = self.tile_size.min(self.height - top);
+            let mut left = 0;
+            while left < self.width {
+                let w = self.tile_size.min(self.width - left);
+                rects.push((left, top, w, h));
+                left += self.tile_size;
+            }
+            top += self.tile_size;
+        }
+        rects
+    }
+}
+
+/// A full-frame buffer assembled one tile at a time.
+pub struct Frame {
+    pub buf: ImgVec<Srgba<u8>>,
+    tiles_remaining: usize,
+}
+
+impl Frame {
+    /// Blit `tile`'s pixels into this frame's buffer at `(tile.left, tile.top)`.
+    pub fn tile_ready(&mut self, tile: &Tile) {
+        let stride = self.buf.stride();
+        let tile_stride = tile.buf.stride();
+        for row in 0..tile.buf.height() {
+            let dst_start = (tile.top + row) * stride + tile.left;
+            let src_start = row * tile_stride;
+            self.buf.buf[dst_start..dst_start + tile.buf.width()]
+                .copy_from_slice(&tile.buf.buf[src_start..src_start + tile.buf.width()]);
+        }
+        self.tiles_remaining = self.tiles_remaining.saturating_sub(1);
+    }
+
+    /// Whether every tile of this frame has landed.
+    pub fn is_done(&self) -> bool {
+        self.tiles_remaining == 0
+    }
+}
+
+/// World and camera for a single frame, plus its sampling budget.
+pub struct FrameData {
+    pub world: World,
+    pub cam: DefocusCamera<PerspectiveCamera>,
+    pub params: SampleParams,
+}
+
+/// A rendered tile, positioned within its frame.
+pub struct Tile {
+    pub frame_num: u32,
+    pub left: usize,
+    pub top: usize,
+    pub buf: ImgVec<Srgba<u8>>,
+}
+
+/// Outcome of a `tick` poll, steering the render loop.
+pub enum TickResult {
+    /// Keep rendering the current frame.
+    Run,
+    /// Stop rendering and return from `render_pipeline`.
+    Exit,
+    /// Abandon every tile in flight for the current frame and re-fetch it from
+    /// `per_frame_world`, so a changed camera is picked up immediately instead
+    /// of after the whole frame finishes.
+    Restart,
+}
+
+/// One tile of work, tagged with the generation it was issued under so stale
+/// results (from before a `Restart`) can be dropped instead of delivered.
+struct Job {
+    generation: usize,
+    frame_num: u32,
+    rect: (usize, usize, usize, usize),
+    data: Arc<FrameData>,
+}
+
+/// A finished tile tagged with the generation it was rendered under.
+struct Finished {
+    generation: usize,
+    tile: Tile,
+}
+
+/// Run the tile scheduler: `per_frame_world(i)` supplies frame `i`'s data (or
+/// `None` to end the run), `on_tile` receives finished tiles in no particular
+/// order, and `tick` is polled roughly every `poll_ms` milliseconds.
+pub fn render_pipeline<FW, OT, TK>(
+    mut per_frame_world: FW,
+    on_tile: OT,
+    mut tick: TK,
+    poll_ms: u64,
+    params: RenderParams,
+) -> Result<(), Error>
+where
+    FW: FnMut(u32) -> Result<Option<FrameData>, Error>,
+    OT: Fn(Tile) -> Result<(), Error>,
+    TK: FnMut() -> TickResult,
+{
+    // bound the job queue to `tile_queue` so a slow preview can't let an
+    // unbounded backlog of in-flight tiles pile up in memory
+    let (job_tx, job_rx) = channel::bounded::<Job>(params.tile_queue.max(1));
+    let (result_tx, result_rx) = channel::unbounded::<Finished>();
+    let generation = Arc::new(AtomicUsize::new(0));
+
+    let workers: Vec<_> = (0..params.threads.max(1)).map(|_| {
+        let job_rx = job_rx.clone();
+        let result_tx = result_tx.clone();
+        let generation = generation.clone();
+        thread::spawn(move || {
+            for job in job_rx.iter() {
+                // a restart bumped the generation after this job was queued:
+                // drop it without rendering rather than waste the sample budget
+                if job.generation != generation.load(Ordering::SeqCst) { continue; }
+                let tile = render_tile(&job);
+                if result_tx.send(Finished { generation: job.generation, tile }).is_err() { return; }
+            }
+        })
+    }).collect();
+
+    let result = (|| -> Result<(), Error> {
+        let mut frame_num = 0u32;
+        'frames: loop {
+            let frame_data = match per_frame_world(frame_num)? {
+                Some(fd) => fd,
+                None => break,
+            };
+            let frame_data = Arc::new(frame_data);
+            let gen = generation.load(Ordering::SeqCst);
+            let rects = params.tile_rects();
+            let total = rects.len();
+            let mut rects = rects.into_iter().peekable();
+
+            let mut completed = 0;
+            let mut last_poll = Instant::now();
+            while completed < total {
+                // top up the bounded job queue without blocking, so a full
+                // queue never holds back `on_tile` or `tick`
+                while let Some(&rect) = rects.peek() {
+                    let job = Job { generation: gen, frame_num, rect, data: frame_data.clone() };
+                    if job_tx.try_send(job).is_err() { break; }
+                    rects.next();
+                }
+
+                // hand every ready, still-current tile to the caller
+                while let Ok(finished) = result_rx.try_recv() {
+                    if finished.generation != gen { continue; }
+                    on_tile(finished.tile)?;
+                    completed += 1;
+                }
+
+                if completed >= total { break; }
+
+                if last_poll.elapsed() >= Duration::from_millis(poll_ms) {
+                    last_poll = Instant::now();
+                    match tick() {
+                        TickResult::Run => (),
+                        TickResult::Exit => break 'frames,
+                        TickResult::Restart => {
+                            // bump the generation so queued/in-flight jobs and
+                            // any results already on their way in are dropped,
+                            // then re-fetch this same frame index fresh
+                            generation.fetch_add(1, Ordering::SeqCst);
+                            continue 'frames;
+                        }
+                    }
+                } else {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+
+            frame_num += 1;
+        }
+        Ok(())
+    })();
+
+    drop(job_tx);
+    for worker in workers { worker.join().ok(); }
+
+    result
+}
+
+/// Render one tile. The actual per-pixel sampling lives in `sample`/`camera`;
+/// this just slices out the tile's buffer and fills it in.
+fn render_tile(job: &Job) -> Tile {
+    let (left, top, width, height) = job.rect;
+    let mut rng = rand::thread_rng();
+    let mut buf = ImgVec::new(vec![Srgba::new(0u8, 0, 0, 255); width * height], width, height);
+    for row in 0..height {
+        for col in 0..width {
+            let color = job.data.world.sample(
+                &job.data.cam,
+                left + col, top + row,
+                job.data.params,
+                &mut rng,
+            );
+            buf.buf[row * width + col] = color;
+        }
+    }
+    Tile { frame_num: job.frame_num, left, top, buf }
+}
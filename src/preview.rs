@@ -0,0 +1,154 @@
+//! Preview pixel-format negotiation.
+//!
+//! The tracer produces frames in a canonical linear-to-sRGB RGBA8 byte layout
+//! (`[R, G, B, A]` per pixel). The renderer, on the other hand, wants pixels in
+//! whatever format its backing texture prefers. This module owns the mapping
+//! between the two so the rest of the preview code never has to reason about
+//! channel order or endianness.
+
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::rect::Rect;
+
+/// Compute an aspect-preserving, centered destination rectangle that fits a
+/// `src`-sized frame inside a `dst`-sized window, letter-/pillar-boxing the
+/// remainder. Mirrors how emulator frontends upscale a fixed framebuffer into an
+/// arbitrary window.
+pub fn fit_rect((sw, sh): (u32, u32), (dw, dh): (u32, u32)) -> Rect {
+    if sw == 0 || sh == 0 { return Rect::new(0, 0, dw, dh); }
+    let scale = (dw as f64 / sw as f64).min(dh as f64 / sh as f64);
+    let w = (sw as f64 * scale).round() as u32;
+    let h = (sh as f64 * scale).round() as u32;
+    let x = (dw as i32 - w as i32) / 2;
+    let y = (dh as i32 - h as i32) / 2;
+    Rect::new(x, y, w, h)
+}
+
+/// A byte-for-byte shuffle from canonical RGBA8 into a target pixel format.
+#[derive(Debug, Clone, Copy)]
+pub struct PixelShuffle {
+    // source channel (0 = R, 1 = G, 2 = B, 3 = A) feeding each destination byte
+    order: [usize; 4],
+}
+
+impl PixelShuffle {
+    /// Build the shuffle for `fmt`, or `None` for a format we don't know how to
+    /// feed from RGBA8.
+    pub fn for_format(fmt: PixelFormatEnum) -> Option<PixelShuffle> {
+        // logical channel order, most-significant byte first
+        let logical: [usize; 4] = match fmt {
+            PixelFormatEnum::ARGB8888 => [3, 0, 1, 2], // A R G B
+            PixelFormatEnum::ABGR8888 => [3, 2, 1, 0], // A B G R
+            PixelFormatEnum::RGBA8888 => [0, 1, 2, 3], // R G B A
+            PixelFormatEnum::BGRA8888 => [2, 1, 0, 3], // B G R A
+            _ => return None,
+        };
+
+        // a u32 format is laid out most-significant-byte-first in memory only on
+        // big-endian targets; little-endian stores the low byte first, so the
+        // in-memory byte order is the logical order reversed. This is the
+        // "OpenGL endianness stupidity" the old hardcoded ABGR8888 path worked
+        // around by hand.
+        let mut order = logical;
+        if cfg!(target_endian = "little") { order.reverse(); }
+        Some(PixelShuffle { order })
+    }
+
+    /// Pick a pixel format to hand the renderer, preferring its native format
+    /// and falling back to `ABGR8888` when we can't map it.
+    pub fn negotiate(preferred: PixelFormatEnum) -> (PixelFormatEnum, PixelShuffle) {
+        match PixelShuffle::for_format(preferred) {
+            Some(shuffle) => (preferred, shuffle),
+            None => (
+                PixelFormatEnum::ABGR8888,
+                PixelShuffle::for_format(PixelFormatEnum::ABGR8888).unwrap(),
+            ),
+        }
+    }
+
+    /// Convert a run of canonical RGBA8 pixels from `src` into `dst`, stopping at
+    /// whichever buffer runs out of whole pixels first.
+    pub fn convert_row(&self, src: &[u8], dst: &mut [u8]) {
+        for (px_src, px_dst) in src.chunks_exact(4).zip(dst.chunks_exact_mut(4)) {
+            for (d, &s) in px_dst.iter_mut().zip(self.order.iter()) {
+                *d = px_src[s];
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fit_rect_pillarboxes_a_wider_window() {
+        // 4:3 source into an 8:3 window: height-limited, centered horizontally
+        let dst = fit_rect((400, 300), (800, 300));
+        assert_eq!((dst.width(), dst.height()), (400, 300));
+        assert_eq!((dst.x(), dst.y()), (200, 0));
+    }
+
+    #[test]
+    fn fit_rect_letterboxes_a_taller_window() {
+        // wide 8:3 source into a square window: width-limited, centered vertically
+        let dst = fit_rect((800, 300), (400, 400));
+        assert_eq!((dst.width(), dst.height()), (400, 150));
+        assert_eq!((dst.x(), dst.y()), (0, 125));
+    }
+
+    #[test]
+    fn fit_rect_handles_zero_size_source() {
+        let dst = fit_rect((0, 0), (640, 480));
+        assert_eq!((dst.width(), dst.height()), (640, 480));
+    }
+
+    #[test]
+    fn convert_row_abgr8888_reverses_channels_and_bytes() {
+        // ABGR8888 logical order is A,B,G,R; little-endian storage reverses
+        // that to R,G,B,A, so on a little-endian target this is a byte-for-byte
+        // identity mapping from the canonical RGBA8 source.
+        let shuffle = PixelShuffle::for_format(PixelFormatEnum::ABGR8888).unwrap();
+        let src = [0x10u8, 0x20, 0x30, 0x40];
+        let mut dst = [0u8; 4];
+        shuffle.convert_row(&src, &mut dst);
+        if cfg!(target_endian = "little") {
+            assert_eq!(dst, src);
+        } else {
+            assert_eq!(dst, [0x40, 0x30, 0x20, 0x10]);
+        }
+    }
+
+    #[test]
+    fn convert_row_argb8888_swaps_byte_order_on_little_endian() {
+        let shuffle = PixelShuffle::for_format(PixelFormatEnum::ARGB8888).unwrap();
+        let src = [0x10u8, 0x20, 0x30, 0x40]; // R G B A
+        let mut dst = [0u8; 4];
+        shuffle.convert_row(&src, &mut dst);
+        if cfg!(target_endian = "little") {
+            assert_eq!(dst, [0x30, 0x20, 0x10, 0x40]); // B G R A
+        } else {
+            assert_eq!(dst, [0x40, 0x10, 0x20, 0x30]); // A R G B
+        }
+    }
+
+    #[test]
+    fn convert_row_stops_at_shortest_buffer() {
+        // identity on a little-endian target (see the ABGR8888 test above),
+        // which keeps the expected output simple regardless of host endianness
+        let shuffle = PixelShuffle::for_format(PixelFormatEnum::ABGR8888).unwrap();
+        let src = [1u8, 2, 3, 4, 5, 6, 7, 8]; // two pixels
+        let mut dst = [0u8; 4]; // room for one
+        shuffle.convert_row(&src, &mut dst);
+        if cfg!(target_endian = "little") {
+            assert_eq!(dst, [1, 2, 3, 4]);
+        } else {
+            assert_eq!(dst, [4, 3, 2, 1]);
+        }
+    }
+
+    #[test]
+    fn negotiate_falls_back_to_abgr8888_for_an_unmapped_format() {
+        let (fmt, _) = PixelShuffle::negotiate(PixelFormatEnum::Index8);
+        assert_eq!(fmt, PixelFormatEnum::ABGR8888);
+    }
+}